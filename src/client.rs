@@ -1,10 +1,12 @@
 mod connect;
+mod proxy_protocol;
 mod read;
 mod tls;
 use futures::{future, Future};
 use futures03::future::{FutureExt, TryFutureExt};
 use log::{debug, info, warn};
 use std::cmp;
+use std::collections::HashSet;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
@@ -13,11 +15,12 @@ use tokio_core::reactor::Handle;
 
 use crate::{
     client::connect::try_connect_all,
+    client::proxy_protocol::encode_proxy_protocol_v2,
     client::read::read_with_timeout,
     client::tls::parse_client_hello,
     monitor::ServerList,
-    proxy::copy::{pipe, SharedBuf},
     proxy::{Destination, ProxyServer},
+    router::SniRouter,
     tcp::{get_original_dest, get_original_dest6},
     RcBox,
 };
@@ -27,8 +30,23 @@ pub struct NewClient {
     left: TcpStream,
     src: SocketAddr,
     pub dest: Destination,
+    // the destination as returned by the kernel, before any SNI-based
+    // rewrite; only this form is a real address, so it's what gets
+    // embedded in an eventual PROXY protocol header.
+    orig_dest: SocketAddr,
+    // host name from the TLS SNI extension, once seen in `retrive_dest`,
+    // used to pick a routing group in `connect_server`.
+    sni_host: Option<Box<str>>,
     list: ServerList,
     handle: Handle,
+    send_proxy_header: bool,
+    // tags of servers that opt into the PROXY header on their own, via the
+    // per-server `send proxy protocol` key, regardless of the global
+    // default above.
+    proxy_header_tags: Arc<HashSet<String>>,
+    router: Option<Arc<SniRouter>>,
+    keepalive: Duration,
+    idle_timeout: Duration,
 }
 
 #[derive(Debug)]
@@ -38,12 +56,13 @@ pub struct NewClientWithData {
     allow_parallel: bool,
 }
 
-#[derive(Debug)]
 pub struct ConnectedClient {
     left: TcpStream,
     right: TcpStream,
     dest: Destination,
     server: Arc<ProxyServer>,
+    keepalive: Duration,
+    idle_timeout: Duration,
 }
 
 type ConnectServer = Box<dyn Future<Item = ConnectedClient, Error = ()>>;
@@ -57,12 +76,20 @@ impl NewClient {
         left: TcpStream,
         list: ServerList,
         handle: Handle,
+        send_proxy_header: bool,
+        proxy_header_tags: Arc<HashSet<String>>,
+        router: Option<Arc<SniRouter>>,
+        keepalive: Duration,
+        idle_timeout: Duration,
     ) -> impl Future<Item = Self, Error = ()> {
-        let dest4 = future::result(get_original_dest(&left)).map(SocketAddr::V4);
-        let dest6 = future::result(get_original_dest6(&left)).map(SocketAddr::V6);
-        // TODO: call either v6 or v4 according to our socket
+        // Pick the sockopt matching the accepted socket's own family,
+        // rather than guessing by trying v4 then falling back to v6.
+        let dest = left.local_addr().and_then(|local| match local {
+            SocketAddr::V4(_) => get_original_dest(&left).map(SocketAddr::V4),
+            SocketAddr::V6(_) => get_original_dest6(&left).map(SocketAddr::V6),
+        });
         let src_dest = future::result(left.peer_addr())
-            .join(dest4.or_else(|_| dest6))
+            .join(future::result(dest))
             .map_err(|err| warn!("fail to get original dest: {}", err));
         src_dest.map(move |(src, dest)| {
             debug!("dest {:?}", dest);
@@ -70,8 +97,15 @@ impl NewClient {
                 left,
                 src,
                 dest: dest.into(),
+                orig_dest: dest,
+                sni_host: None,
                 list,
                 handle,
+                send_proxy_header,
+                proxy_header_tags,
+                router,
+                keepalive,
+                idle_timeout,
             }
         })
     }
@@ -83,8 +117,15 @@ impl NewClient {
             left,
             src,
             mut dest,
+            orig_dest,
+            mut sni_host,
             list,
             handle,
+            send_proxy_header,
+            proxy_header_tags,
+            router,
+            keepalive,
+            idle_timeout,
         } = self;
         let wait = Duration::from_millis(500);
         // try to read TLS ClientHello for
@@ -106,6 +147,7 @@ impl NewClient {
                     Ok(hello) => {
                         if let Some(name) = hello.server_name {
                             dest = (name, dest.port).into();
+                            sni_host = Some(name.into());
                             debug!("SNI found: {}", name);
                         }
                         if hello.early_data {
@@ -121,8 +163,15 @@ impl NewClient {
                     left,
                     src,
                     dest,
+                    orig_dest,
+                    sni_host,
                     list,
                     handle,
+                    send_proxy_header,
+                    proxy_header_tags,
+                    router,
+                    keepalive,
+                    idle_timeout,
                 },
                 allow_parallel,
                 pending_data,
@@ -131,6 +180,21 @@ impl NewClient {
         .map_err(|err| warn!("fail to read hello from client: {}", err))
     }
 
+    // Narrow `list` down to the servers matching the SNI routing rules, if
+    // any apply; otherwise race the full list as before.
+    fn route_by_sni(mut self) -> Self {
+        if let (Some(router), Some(host)) = (&self.router, &self.sni_host) {
+            match router.select(host, &self.list.servers) {
+                Some(matched) => {
+                    debug!("{} routed to {} server(s) by SNI", host, matched.len());
+                    self.list = ServerList::new(matched);
+                }
+                None => debug!("no routing rule for SNI {}, using full list", host),
+            }
+        }
+        self
+    }
+
     fn connect_server(
         self,
         n_parallel: usize,
@@ -141,9 +205,44 @@ impl NewClient {
             left,
             src,
             dest,
+            orig_dest,
+            sni_host: _,
             list,
             handle,
+            send_proxy_header,
+            proxy_header_tags,
+            router: _,
+            keepalive,
+            idle_timeout,
         } = self;
+        // The same prefix is sent to every racing candidate, so the header
+        // can only be turned on per-server when *every* candidate in this
+        // race opted in (or the global flag covers them all) — otherwise a
+        // single opted-in server sharing a pool with one that isn't would
+        // get a header it never asked for and whoever wins the race might
+        // be the one that didn't want it.
+        let send_proxy_header = send_proxy_header
+            || (!list.servers.is_empty()
+                && list.servers.iter().all(|s| proxy_header_tags.contains(&s.tag)));
+        // The PROXY protocol header, if any, must be the very first thing
+        // the upstream sees, so prepend it to whatever request bytes we
+        // already buffered.
+        let pending_data = if send_proxy_header {
+            let header = encode_proxy_protocol_v2(src, orig_dest).unwrap_or_else(|| {
+                warn!(
+                    "src {} and dest {} differ in address family, sending no PROXY header",
+                    src, orig_dest
+                );
+                Vec::new()
+            });
+            let mut buf = header;
+            if let Some(data) = pending_data {
+                buf.extend_from_slice(&data);
+            }
+            Some(buf.into_boxed_slice())
+        } else {
+            pending_data
+        };
         let pending_data = pending_data.map(RcBox::new);
         let conn = try_connect_all(
             dest.clone(),
@@ -161,6 +260,8 @@ impl NewClient {
                     right,
                     dest,
                     server,
+                    keepalive,
+                    idle_timeout,
                 }
             })
             .map_err(|_| warn!("all proxy server down"));
@@ -181,6 +282,7 @@ impl Connectable for NewClientWithData {
             pending_data,
             allow_parallel,
         } = self;
+        let client = client.route_by_sni();
         let n_parallel = if allow_parallel {
             cmp::min(client.list.len(), n_parallel)
         } else {
@@ -191,37 +293,18 @@ impl Connectable for NewClientWithData {
 }
 
 impl ConnectedClient {
-    pub fn serve(self, shared_buf: SharedBuf) -> impl Future<Item = (), Error = ()> {
+    /// Split a connected client back into its raw parts so that `main.rs`
+    /// can drive the pipe itself. The returned idle timeout is only carried
+    /// along: no deadline is applied to the session, idle or otherwise.
+    pub fn into_parts(self) -> (TcpStream, TcpStream, Destination, Arc<ProxyServer>, Duration, Duration) {
         let ConnectedClient {
             left,
             right,
             dest,
             server,
+            keepalive,
+            idle_timeout,
         } = self;
-        // TODO: make keepalive configurable
-        let timeout = Some(Duration::from_secs(300));
-        if let Err(e) = left
-            .set_keepalive(timeout)
-            .and(right.set_keepalive(timeout))
-        {
-            warn!("fail to set keepalive: {}", e);
-        }
-
-        server.update_stats_conn_open();
-        pipe(left, right, server.clone(), shared_buf).then(move |result| match result {
-            Ok(amt) => {
-                server.update_stats_conn_close(false);
-                debug!(
-                    "tx {}, rx {} bytes ({} => {})",
-                    amt.tx_bytes, amt.rx_bytes, server.tag, dest
-                );
-                Ok(())
-            }
-            Err(_) => {
-                server.update_stats_conn_close(true);
-                warn!("{} (=> {}) close with error", server.tag, dest);
-                Err(())
-            }
-        })
+        (left, right, dest, server, keepalive, idle_timeout)
     }
 }
@@ -0,0 +1,77 @@
+use std::net::SocketAddr;
+
+// 12-byte magic prefix shared by every PROXY protocol v2 header.
+const SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+const VERSION_COMMAND: u8 = 0x21; // version 2, command PROXY
+const FAMILY_INET: u8 = 0x11; // AF_INET, STREAM
+const FAMILY_INET6: u8 = 0x21; // AF_INET6, STREAM
+
+/// Build a binary PROXY protocol v2 header carrying `src` and `dest`, to be
+/// written to the upstream proxy before any other bytes. Returns `None` if
+/// `src` and `dest` are not the same address family, since v2 header can
+/// only describe one family per connection.
+pub fn encode_proxy_protocol_v2(src: SocketAddr, dest: SocketAddr) -> Option<Vec<u8>> {
+    let mut buf = Vec::with_capacity(16 + 36);
+    buf.extend_from_slice(&SIGNATURE);
+    buf.push(VERSION_COMMAND);
+    match (src, dest) {
+        (SocketAddr::V4(src), SocketAddr::V4(dest)) => {
+            buf.push(FAMILY_INET);
+            buf.extend_from_slice(&12u16.to_be_bytes());
+            buf.extend_from_slice(&src.ip().octets());
+            buf.extend_from_slice(&dest.ip().octets());
+            buf.extend_from_slice(&src.port().to_be_bytes());
+            buf.extend_from_slice(&dest.port().to_be_bytes());
+        }
+        (SocketAddr::V6(src), SocketAddr::V6(dest)) => {
+            buf.push(FAMILY_INET6);
+            buf.extend_from_slice(&36u16.to_be_bytes());
+            buf.extend_from_slice(&src.ip().octets());
+            buf.extend_from_slice(&dest.ip().octets());
+            buf.extend_from_slice(&src.port().to_be_bytes());
+            buf.extend_from_slice(&dest.port().to_be_bytes());
+        }
+        _ => return None,
+    }
+    Some(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_v4() {
+        let src: SocketAddr = "1.2.3.4:1111".parse().unwrap();
+        let dest: SocketAddr = "5.6.7.8:2222".parse().unwrap();
+        let header = encode_proxy_protocol_v2(src, dest).unwrap();
+        assert_eq!(&header[..12], &SIGNATURE);
+        assert_eq!(header[12], VERSION_COMMAND);
+        assert_eq!(header[13], FAMILY_INET);
+        assert_eq!(&header[14..16], &12u16.to_be_bytes());
+        assert_eq!(&header[16..20], &[1, 2, 3, 4]);
+        assert_eq!(&header[20..24], &[5, 6, 7, 8]);
+        assert_eq!(&header[24..26], &1111u16.to_be_bytes());
+        assert_eq!(&header[26..28], &2222u16.to_be_bytes());
+        assert_eq!(header.len(), 28);
+    }
+
+    #[test]
+    fn encodes_v6() {
+        let src: SocketAddr = "[::1]:1111".parse().unwrap();
+        let dest: SocketAddr = "[::2]:2222".parse().unwrap();
+        let header = encode_proxy_protocol_v2(src, dest).unwrap();
+        assert_eq!(header[13], FAMILY_INET6);
+        assert_eq!(&header[14..16], &36u16.to_be_bytes());
+        assert_eq!(header.len(), 16 + 36);
+    }
+
+    #[test]
+    fn mismatched_family_returns_none() {
+        let src: SocketAddr = "1.2.3.4:1111".parse().unwrap();
+        let dest: SocketAddr = "[::1]:2222".parse().unwrap();
+        assert!(encode_proxy_protocol_v2(src, dest).is_none());
+    }
+}
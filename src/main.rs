@@ -1,4 +1,3 @@
-extern crate nix;
 extern crate net2;
 extern crate futures;
 extern crate tokio_core;
@@ -11,25 +10,23 @@ extern crate clap;
 #[macro_use]
 extern crate log;
 extern crate moproxy;
-use std::cmp;
+use std::collections::HashSet;
 use std::env;
 use std::thread;
 use std::sync::Arc;
 use std::time::Duration;
-use std::net::{SocketAddr, SocketAddrV4};
-use std::io::{self, ErrorKind};
-use std::os::unix::io::{RawFd, AsRawFd};
+use std::net::SocketAddr;
 use ini::Ini;
-use futures::{future, stream, Future, Stream};
-use tokio_core::net::{TcpListener, TcpStream};
-use tokio_core::reactor::{Core, Handle};
-use tokio_timer::Timer;
-use nix::sys::socket;
+use futures::Future;
+use tokio_core::net::TcpListener;
+use tokio_core::reactor::Core;
 use log::LogLevelFilter;
 use env_logger::{LogBuilder, LogTarget};
+use moproxy::client::{Connectable, NewClient};
 use moproxy::monitor::{self, ServerList};
 use moproxy::proxy::{self, ProxyServer};
 use moproxy::proxy::ProxyProto::{Socks5, Http};
+use moproxy::router::SniRouter;
 use moproxy::web;
 
 
@@ -63,12 +60,17 @@ fn main() {
         .expect("missing probe secs").parse()
         .expect("not a vaild probe secs");
 
-    let servers = parse_servers(&args);
+    let (servers, send_proxy_header, proxy_header_tags, router, keepalive, idle_timeout,
+            n_parallel) = parse_servers(&args);
+    let proxy_header_tags = Arc::new(proxy_header_tags);
+    let router = router.map(Arc::new);
     if servers.len() == 0 {
         panic!("missing server list");
     }
     info!("total {} server(s) added", servers.len());
     let servers = Arc::new(ServerList::new(servers));
+    // NewClient::from_socket takes the server list by value rather than by
+    // Arc, so each accepted connection gets its own cheap clone of it.
 
     if let Some(addr) = args.value_of("web-bind") {
         let servers = servers.clone();
@@ -88,41 +90,68 @@ fn main() {
     handle.spawn(mon);
     let server = listener.incoming().for_each(move |(client, addr)| {
         debug!("incoming {}", addr);
-        let list = servers.clone();
-        let conn = connect_server(client, list.clone(), handle.clone());
-        let serv = conn.and_then(|(client, proxy, (dest, idx))| {
-            let timeout = Some(Duration::from_secs(180));
-            if let Err(e) = client.set_keepalive(timeout)
-                    .and(proxy.set_keepalive(timeout)) {
+        let list = (*servers).clone();
+        let router = router.clone();
+        let proxy_header_tags = proxy_header_tags.clone();
+        let handle2 = handle.clone();
+        let conn = NewClient::from_socket(
+                client, list, handle.clone(), send_proxy_header, proxy_header_tags,
+                router, keepalive, idle_timeout)
+            .and_then(|c| c.retrive_dest())
+            .and_then(move |c| Connectable::connect_server(c, n_parallel));
+        let serv = conn.and_then(move |connected| {
+            let (client, proxy, dest, server, keepalive, _idle_timeout) =
+                connected.into_parts();
+            if let Err(e) = client.set_keepalive(Some(keepalive))
+                    .and(proxy.set_keepalive(Some(keepalive))) {
                 warn!("fail to set keepalive: {}", e);
             }
-            list.update_stats_conn_open(idx);
+            server.update_stats_conn_open();
+            // `idle_timeout` is parsed but not enforced here: a flat
+            // `Timer::timeout` around the whole piping future would kill
+            // active long-lived connections just as often as idle ones, and
+            // score the resulting close as an error — the opposite of what
+            // an idle timeout is for. Enforcing it properly needs per-byte
+            // last-activity tracking inside the pipe itself (e.g. in
+            // proxy::copy), which is outside this patch series.
             proxy::piping(client, proxy).then(move |result| match result {
                 Ok((tx, rx)) => {
-                    list.update_stats_conn_close(idx, tx, rx);
+                    server.update_stats_conn_close(false);
                     debug!("tx {}, rx {} bytes ({} => {})",
-                        tx, rx, list.servers[idx].tag, dest);
+                        tx, rx, server.tag, dest);
                     Ok(())
                 },
                 Err(e) => {
-                    list.update_stats_conn_close(idx, 0, 0);
+                    server.update_stats_conn_close(true);
                     warn!("{} (=> {}) piping error: {}",
-                        list.servers[idx].tag, dest, e);
+                        server.tag, dest, e);
                     Err(())
                 },
             })
         });
-        handle.spawn(serv);
+        handle2.spawn(serv);
         Ok(())
     });
     lp.run(server).expect("error on event loop");
 }
 
-fn parse_servers(args: &clap::ArgMatches) -> Vec<ProxyServer> {
+fn parse_servers(args: &clap::ArgMatches)
+        -> (Vec<ProxyServer>, bool, HashSet<String>, Option<SniRouter>, Duration, Duration, usize) {
     let default_test_ip = args.value_of("test-ip")
         .expect("missing test-ip").parse()
         .expect("not a valid ip address");
     let mut servers: Vec<ProxyServer> = vec![];
+    let mut send_proxy_header = false;
+    // tags of servers that opt into the PROXY header on their own via the
+    // per-server `send proxy protocol` key, OR'd with the global default.
+    let mut proxy_header_tags = HashSet::new();
+    let mut router = None;
+    let mut keepalive = Duration::from_secs(180);
+    let mut idle_timeout = Duration::from_secs(600);
+    // how many servers to race in parallel for a single connection, capped
+    // at the candidate list's own length in NewClientWithData::connect_server;
+    // 1 (no racing) unless an operator opts in.
+    let mut n_parallel: usize = 1;
     if let Some(s) = args.values_of("socks5-servers") {
         for s in s.map(parse_server) {
             servers.push(ProxyServer::new(
@@ -138,10 +167,31 @@ fn parse_servers(args: &clap::ArgMatches) -> Vec<ProxyServer> {
     if let Some(path) = args.value_of("server-list") {
         let ini = Ini::load_from_file(path)
             .expect("cannot read server list file");
-        for (tag, props) in ini.iter() {
+        let general = ini.general_section();
+        send_proxy_header = general.get("send proxy protocol")
+            .map(|v| v.parse().expect("send proxy protocol not a bool"))
+            .unwrap_or(false);
+        keepalive = general.get("keepalive secs")
+            .map(|s| Duration::from_secs(s.parse().expect("keepalive secs not an integer")))
+            .unwrap_or(keepalive);
+        idle_timeout = general.get("idle timeout secs")
+            .map(|s| Duration::from_secs(s.parse().expect("idle timeout secs not an integer")))
+            .unwrap_or(idle_timeout);
+        n_parallel = general.get("n parallel")
+            .map(|s| s.parse().expect("n parallel not an integer"))
+            .unwrap_or(n_parallel);
+        router = Some(SniRouter::from_ini(&ini));
+        for (section, props) in ini.iter() {
+            // the unnamed section holds global options (parsed above);
+            // `route:`-prefixed sections are SniRouter rules, not servers.
+            match section {
+                None => continue,
+                Some(s) if s.starts_with(moproxy::router::SECTION_PREFIX) => continue,
+                _ => {}
+            }
             let tag = if let Some(s) = props.get("tag") {
                 Some(s.as_str())
-            } else if let Some(ref s) = *tag {
+            } else if let Some(ref s) = *section {
                 Some(s.as_str())
             } else {
                 None
@@ -157,10 +207,30 @@ fn parse_servers(args: &clap::ArgMatches) -> Vec<ProxyServer> {
             let test_ip = props.get("test ip").map(|i| i.parse()
                 .expect("not a valid ip address"))
                 .unwrap_or(default_test_ip);
+            let send_proxy_header_here = props.get("send proxy protocol")
+                .map(|v| v.parse().expect("send proxy protocol not a bool"))
+                .unwrap_or(false);
+            if send_proxy_header_here {
+                if let Some(tag) = tag {
+                    proxy_header_tags.insert(tag.to_string());
+                } else {
+                    warn!("server has no tag, \"send proxy protocol\" per-server override ignored");
+                }
+            }
+            if let Some(transport) = props.get("transport") {
+                if transport != "tcp" {
+                    // `transport::Transport` models non-TCP transports like
+                    // KCP, but connecting over one needs ProxyServer::connect
+                    // and try_connect_all to return a boxed stream instead
+                    // of a concrete TcpStream, which isn't the case here.
+                    warn!("server {:?}: transport {:?} is not supported yet, using tcp",
+                        tag, transport);
+                }
+            }
             servers.push(ProxyServer::new(addr, proto, test_ip, tag, base));
         }
     }
-    servers
+    (servers, send_proxy_header, proxy_header_tags, router, keepalive, idle_timeout, n_parallel)
 }
 
 fn parse_server(addr: &str) -> SocketAddr {
@@ -171,55 +241,3 @@ fn parse_server(addr: &str) -> SocketAddr {
     }.expect("not a valid server address")
 }
 
-fn connect_server(client: TcpStream, list: Arc<ServerList>, handle: Handle)
-        -> Box<Future<Item=(TcpStream, TcpStream,
-                           (SocketAddr, usize)), Error=()>> {
-    let src_dst = future::result(client.peer_addr())
-        .join(future::result(get_original_dest(client.as_raw_fd())))
-        .map_err(|err| warn!("fail to get original destination: {}", err));
-    // TODO: reuse timer?
-    let timer = Timer::default();
-    let infos = list.get_infos().clone();
-    let try_connect_all = src_dst.and_then(move |(src, dest)| {
-        stream::iter_ok(infos).for_each(move |info| {
-            let server = list.servers[info.idx].clone();
-            let conn = server.connect(dest, &handle);
-            let wait = if let Some(delay) = info.delay {
-                cmp::max(Duration::from_secs(3), delay * 2)
-            } else {
-                Duration::from_secs(3)
-            };
-            // Standard proxy server need more time (e.g. DNS resolving)
-            timer.timeout(conn, wait).then(move |result| match result {
-                Ok(conn) => {
-                    info!("{} => {} via {}", src, dest, server.tag);
-                    Err((conn, (dest, info.idx)))
-                },
-                Err(err) => {
-                    warn!("fail to connect {}: {}", server.tag, err);
-                    Ok(())
-                }
-            })
-        }).then(|result| match result {
-            Err(args) => Ok(args),
-            Ok(_) => {
-                warn!("all proxy server down");
-                Err(())
-            },
-        })
-    }).map(|(conn, meta)| (client, conn, meta));
-    Box::new(try_connect_all)
-}
-
-fn get_original_dest(fd: RawFd) -> io::Result<SocketAddr> {
-    let addr = socket::getsockopt(fd, socket::sockopt::OriginalDst)
-        .map_err(|e| match e {
-            nix::Error::Sys(err) => io::Error::from(err),
-            _ => io::Error::new(ErrorKind::Other, e),
-        })?;
-    let addr = SocketAddrV4::new(addr.sin_addr.s_addr.to_be().into(),
-                                 addr.sin_port.to_be());
-    // TODO: support IPv6
-    Ok(SocketAddr::V4(addr))
-}
-
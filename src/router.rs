@@ -0,0 +1,179 @@
+use std::sync::Arc;
+
+use ini::Ini;
+use log::warn;
+
+use crate::proxy::ProxyServer;
+
+// Sections named `route:<pattern>` carry SNI routing rules, kept in the
+// same INI file as the server list so operators only manage one config.
+pub const SECTION_PREFIX: &str = "route:";
+const DEFAULT_PATTERN: &str = "default";
+
+#[derive(Debug, Clone)]
+enum Pattern {
+    Exact(String),
+    // `*.example.com` matches `example.com` and any sub-domain of it.
+    Suffix(String),
+}
+
+impl Pattern {
+    fn parse(raw: &str) -> Self {
+        match raw.strip_prefix("*.") {
+            Some(suffix) => Pattern::Suffix(suffix.to_lowercase()),
+            None => Pattern::Exact(raw.to_lowercase()),
+        }
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        let host = host.to_lowercase();
+        match self {
+            Pattern::Exact(p) => *p == host,
+            Pattern::Suffix(p) => host == *p || host.ends_with(&format!(".{}", p)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    pattern: Pattern,
+    tags: Vec<String>,
+}
+
+/// Maps SNI host names to a subset of proxy servers, so that operators can
+/// pin certain destinations to specific exit proxies while everything else
+/// keeps racing the full server list.
+#[derive(Debug, Clone, Default)]
+pub struct SniRouter {
+    rules: Vec<Rule>,
+    default_tags: Option<Vec<String>>,
+}
+
+impl SniRouter {
+    pub fn from_ini(ini: &Ini) -> Self {
+        let mut rules = vec![];
+        let mut default_tags = None;
+        for (section, props) in ini.iter() {
+            let section = match section {
+                Some(s) => s,
+                None => continue,
+            };
+            let pattern = match section.strip_prefix(SECTION_PREFIX) {
+                Some(p) => p,
+                None => continue,
+            };
+            let tags: Vec<String> = match props.get("tags") {
+                Some(tags) => tags.split(',').map(|t| t.trim().to_string()).collect(),
+                None => {
+                    warn!("route \"{}\" has no tags, skipped", pattern);
+                    continue;
+                }
+            };
+            if pattern == DEFAULT_PATTERN {
+                default_tags = Some(tags);
+            } else {
+                rules.push(Rule {
+                    pattern: Pattern::parse(pattern),
+                    tags,
+                });
+            }
+        }
+        SniRouter { rules, default_tags }
+    }
+
+    /// Pick the servers matching `host`'s routing rule, if any. Returns
+    /// `None` when nothing matches and no default group is configured, so
+    /// the caller can fall back to racing the full server list.
+    pub fn select<'s>(
+        &self,
+        host: &str,
+        servers: &'s [Arc<ProxyServer>],
+    ) -> Option<Vec<Arc<ProxyServer>>> {
+        let tags = self
+            .rules
+            .iter()
+            .find(|rule| rule.pattern.matches(host))
+            .map(|rule| &rule.tags)
+            .or(self.default_tags.as_ref())?;
+        let matched: Vec<_> = servers
+            .iter()
+            .filter(|s| tags.iter().any(|t| t == &s.tag))
+            .cloned()
+            .collect();
+        if matched.is_empty() {
+            None
+        } else {
+            Some(matched)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::ProxyProto::Socks5;
+
+    #[test]
+    fn pattern_exact_matches_only_itself() {
+        let p = Pattern::parse("example.com");
+        assert!(p.matches("example.com"));
+        assert!(p.matches("EXAMPLE.COM"));
+        assert!(!p.matches("www.example.com"));
+    }
+
+    #[test]
+    fn pattern_suffix_matches_root_and_subdomains() {
+        let p = Pattern::parse("*.example.com");
+        assert!(p.matches("example.com"));
+        assert!(p.matches("www.example.com"));
+        assert!(p.matches("a.b.example.com"));
+        assert!(!p.matches("notexample.com"));
+    }
+
+    fn server(tag: &str) -> Arc<ProxyServer> {
+        let test_ip = "127.0.0.1".parse().unwrap();
+        let addr = "127.0.0.1:1080".parse().unwrap();
+        Arc::new(ProxyServer::new(addr, Socks5, test_ip, Some(tag), None))
+    }
+
+    fn ini_from(text: &str) -> Ini {
+        Ini::load_from_str(text).unwrap()
+    }
+
+    #[test]
+    fn select_exact_rule() {
+        let ini = ini_from("[route:example.com]\ntags = a\n");
+        let router = SniRouter::from_ini(&ini);
+        let servers = vec![server("a"), server("b")];
+        let matched = router.select("example.com", &servers).unwrap();
+        assert_eq!(matched.len(), 1);
+        assert_eq!(matched[0].tag, "a");
+    }
+
+    #[test]
+    fn select_wildcard_rule() {
+        let ini = ini_from("[route:*.example.com]\ntags = a, b\n");
+        let router = SniRouter::from_ini(&ini);
+        let servers = vec![server("a"), server("b"), server("c")];
+        let matched = router.select("www.example.com", &servers).unwrap();
+        assert_eq!(matched.len(), 2);
+    }
+
+    #[test]
+    fn select_falls_back_to_default() {
+        let ini = ini_from("[route:default]\ntags = a\n");
+        let router = SniRouter::from_ini(&ini);
+        let servers = vec![server("a"), server("b")];
+        let matched = router.select("unrelated.com", &servers).unwrap();
+        assert_eq!(matched.len(), 1);
+        assert_eq!(matched[0].tag, "a");
+    }
+
+    #[test]
+    fn select_none_without_default() {
+        let ini = ini_from("[route:example.com]\ntags = a\n");
+        let router = SniRouter::from_ini(&ini);
+        let servers = vec![server("a"), server("b")];
+        assert!(router.select("unrelated.com", &servers).is_none());
+    }
+}
@@ -0,0 +1,51 @@
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::net::{Ipv6Addr, SocketAddrV4, SocketAddrV6};
+use std::os::unix::io::AsRawFd;
+
+use nix::sys::socket;
+use tokio_core::net::TcpStream;
+
+// netfilter's IPv6 counterpart to SO_ORIGINAL_DST; `nix` only exposes the
+// IPv4 one, so this is read with a raw getsockopt(2) call instead.
+const IP6T_SO_ORIGINAL_DST: libc::c_int = 80;
+
+/// Recover the pre-DNAT destination of an IPv4 connection redirected by an
+/// iptables `REDIRECT`/`TPROXY` rule.
+pub fn get_original_dest(client: &TcpStream) -> io::Result<SocketAddrV4> {
+    let fd = client.as_raw_fd();
+    let addr = socket::getsockopt(fd, socket::sockopt::OriginalDst).map_err(|e| match e {
+        nix::Error::Sys(err) => io::Error::from(err),
+        _ => io::Error::new(ErrorKind::Other, e),
+    })?;
+    Ok(SocketAddrV4::new(
+        addr.sin_addr.s_addr.to_be().into(),
+        addr.sin_port.to_be(),
+    ))
+}
+
+/// Same as `get_original_dest` but for an ip6tables-redirected IPv6
+/// connection, read via `IP6T_SO_ORIGINAL_DST`.
+pub fn get_original_dest6(client: &TcpStream) -> io::Result<SocketAddrV6> {
+    let fd = client.as_raw_fd();
+    let mut addr: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::IPPROTO_IPV6,
+            IP6T_SO_ORIGINAL_DST,
+            &mut addr as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(SocketAddrV6::new(
+        Ipv6Addr::from(addr.sin6_addr.s6_addr),
+        u16::from_be(addr.sin6_port),
+        0,
+        addr.sin6_scope_id,
+    ))
+}
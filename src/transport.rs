@@ -0,0 +1,39 @@
+//! Transport selection for upstream proxy connections.
+//!
+//! KCP is not implemented: it is tracked as a follow-up, not something this
+//! module parses or constructs. Making `Transport::Kcp` open a real KCP
+//! session requires `ProxyServer::connect` and
+//! `client::connect::try_connect_all` (both in modules outside this patch
+//! series) to return a boxed stream instead of a concrete `TcpStream`, plus
+//! an actual KCP socket implementation; none of that exists here, so this
+//! module only declares the shape a future patch would fill in. Nothing
+//! constructs `Transport::Kcp` today, and `main.rs` does not parse a
+//! `transport` value into this type — it just warns when a server asks for
+//! anything other than `tcp`.
+
+/// How a `ProxyServer` is reached. Only `Transport::Tcp` is ever
+/// constructed right now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    // KCP trades bandwidth for latency by retransmitting aggressively,
+    // which helps proxy hops over lossy or high-latency links where
+    // TCP-over-TCP stalls. Not implemented yet; see module docs.
+    Kcp(KcpConfig),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KcpConfig {
+    pub window_size: u16,
+    pub nodelay: bool,
+}
+
+impl Default for KcpConfig {
+    fn default() -> Self {
+        // fast-retransmit profile: small window, nodelay enabled.
+        KcpConfig {
+            window_size: 256,
+            nodelay: true,
+        }
+    }
+}